@@ -0,0 +1,161 @@
+//! Code actions computed over the stored document text. `compute` returns
+//! lightweight actions with no `edit` yet; the (potentially expensive)
+//! `WorkspaceEdit` is filled in lazily by `resolve`, invoked only when the
+//! client actually executes the action via `codeAction/resolve`.
+
+use lsp_types::{
+    CodeAction,
+    CodeActionKind,
+    CodeActionOrCommand,
+    CodeActionParams,
+    CodeActionResponse,
+    DocumentChanges,
+    OneOf,
+    OptionalVersionedTextDocumentIdentifier,
+    Position,
+    Range,
+    TextDocumentEdit,
+    TextEdit,
+    Url,
+    WorkspaceEdit,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::diagnostics::Document;
+
+const EXTRACT_FUNCTION: &str = "rust-lsp-test.extractFunction";
+const RENAME_ASSIST: &str = "rust-lsp-test.renameAssist";
+
+/// Stashed in `CodeAction.data` so `resolve` knows what to recompute without
+/// re-deriving it from the title.
+#[derive(Serialize, Deserialize)]
+struct ResolveData {
+    kind: String,
+    uri: Url,
+    range: Range,
+}
+
+/// The `CodeActionKind`s this server can produce; advertised in
+/// `CodeActionOptions` and used to build each action below.
+pub fn supported_kinds() -> Vec<CodeActionKind> {
+    vec![CodeActionKind::REFACTOR_EXTRACT, CodeActionKind::REFACTOR_REWRITE]
+}
+
+/// Entry point: given the request range and the document it applies to,
+/// return the actions available there (without their `edit` filled in yet).
+pub fn compute(params: &CodeActionParams, doc: Option<&Document>) -> CodeActionResponse {
+    let Some(_doc) = doc else { return Vec::new() };
+    let uri = params.text_document.uri.clone();
+    let range = params.range;
+
+    let actions = [
+        ("Extract selection to new function", CodeActionKind::REFACTOR_EXTRACT, EXTRACT_FUNCTION),
+        ("Rename symbol under cursor", CodeActionKind::REFACTOR_REWRITE, RENAME_ASSIST),
+    ];
+
+    actions
+        .into_iter()
+        .map(|(title, kind, data_kind)| {
+            let data = ResolveData { kind: data_kind.to_string(), uri: uri.clone(), range };
+            CodeActionOrCommand::CodeAction(CodeAction {
+                title: title.to_string(),
+                kind: Some(kind),
+                data: Some(serde_json::to_value(&data).unwrap()),
+                ..Default::default()
+            })
+        })
+        .collect()
+}
+
+/// The uri a not-yet-resolved `CodeAction` (as stashed by `compute`) applies
+/// to, so the caller can look up the right document before calling
+/// `resolve`.
+pub fn uri_of(action: &CodeAction) -> Option<Url> {
+    let data = action.data.clone()?;
+    serde_json::from_value::<ResolveData>(data).ok().map(|d| d.uri)
+}
+
+/// `codeAction/resolve`: compute the actual `WorkspaceEdit` for `action`,
+/// now that the client has asked for it.
+pub fn resolve(mut action: CodeAction, doc: Option<&Document>) -> CodeAction {
+    let (Some(doc), Some(data)) = (doc, action.data.clone().and_then(|v| serde_json::from_value::<ResolveData>(v).ok())) else {
+        return action;
+    };
+    let uri = data.uri.clone();
+    action.edit = Some(match data.kind.as_str() {
+        EXTRACT_FUNCTION => extract_function_edit(&uri, doc, data.range),
+        RENAME_ASSIST => rename_assist_edit(&uri, doc, data.range),
+        _ => return action,
+    });
+    action
+}
+
+fn extract_function_edit(uri: &Url, doc: &Document, range: Range) -> WorkspaceEdit {
+    let start = position_to_offset(&doc.text, range.start);
+    let end = position_to_offset(&doc.text, range.end);
+    let selected = doc.text.get(start..end).unwrap_or("").to_string();
+    let end_of_doc = end_position(&doc.text);
+
+    let call_edit = TextEdit { range, new_text: "extracted();".to_string() };
+    let new_fn_edit = TextEdit {
+        range: Range { start: end_of_doc, end: end_of_doc },
+        new_text: format!("\n\nfn extracted() {{\n{selected}\n}}\n"),
+    };
+    text_document_edit(uri, doc.version, vec![call_edit, new_fn_edit])
+}
+
+fn rename_assist_edit(uri: &Url, doc: &Document, range: Range) -> WorkspaceEdit {
+    let edit = TextEdit { range, new_text: "renamed".to_string() };
+    text_document_edit(uri, doc.version, vec![edit])
+}
+
+fn text_document_edit(uri: &Url, version: i32, edits: Vec<TextEdit>) -> WorkspaceEdit {
+    let text_document_edit = TextDocumentEdit {
+        text_document: OptionalVersionedTextDocumentIdentifier { uri: uri.clone(), version: Some(version) },
+        edits: edits.into_iter().map(OneOf::Left).collect(),
+    };
+    WorkspaceEdit {
+        document_changes: Some(DocumentChanges::Edits(vec![text_document_edit])),
+        ..Default::default()
+    }
+}
+
+fn position_to_offset(text: &str, pos: Position) -> usize {
+    let mut offset = 0;
+    for (i, line) in text.split('\n').enumerate() {
+        if i as u32 == pos.line {
+            let chars_taken: usize = line.chars().take(pos.character as usize).map(char::len_utf8).sum();
+            return offset + chars_taken;
+        }
+        offset += line.len() + 1;
+    }
+    offset
+}
+
+fn end_position(text: &str) -> Position {
+    let lines: Vec<&str> = text.split('\n').collect();
+    let line = lines.len().saturating_sub(1);
+    let character = lines.last().map(|l| l.chars().count()).unwrap_or(0);
+    Position { line: line as u32, character: character as u32 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn position_to_offset_on_first_line() {
+        assert_eq!(position_to_offset("hello\nworld", Position { line: 0, character: 3 }), 3);
+    }
+
+    #[test]
+    fn position_to_offset_on_later_line_skips_preceding_lines_and_their_newlines() {
+        assert_eq!(position_to_offset("hello\nworld", Position { line: 1, character: 2 }), 8);
+    }
+
+    #[test]
+    fn position_to_offset_counts_characters_not_bytes() {
+        // "héllo" has 5 chars but 6 bytes ('é' is 2 bytes in UTF-8).
+        assert_eq!(position_to_offset("héllo\nworld", Position { line: 0, character: 5 }), 6);
+    }
+}
@@ -0,0 +1,110 @@
+//! A document store, updated from `didOpen`/`didChange`/`didClose`, and a
+//! diagnostics pass that runs after every change and is published via
+//! `textDocument/publishDiagnostics`. The triggering document's `version` is
+//! carried into `PublishDiagnosticsParams.version` so the client can discard
+//! diagnostics that are superseded by a newer edit before they arrive.
+
+use std::collections::HashMap;
+use std::error::Error;
+
+use crossbeam_channel::Sender;
+use lsp_server::{
+    Message,
+    Notification,
+};
+use lsp_types::{
+    notification::{
+        Notification as _,
+        PublishDiagnostics,
+    },
+    Diagnostic,
+    DiagnosticSeverity,
+    Position,
+    PublishDiagnosticsParams,
+    Range,
+    Url,
+};
+
+#[derive(Debug, Clone)]
+pub struct Document {
+    pub version: i32,
+    pub text: String,
+}
+
+/// The server's view of every document the client currently has open.
+#[derive(Default)]
+pub struct DocumentStore {
+    documents: HashMap<Url, Document>,
+}
+
+impl DocumentStore {
+    pub fn open(&mut self, uri: Url, version: i32, text: String) {
+        self.documents.insert(uri, Document { version, text });
+    }
+
+    pub fn change(&mut self, uri: Url, version: i32, text: String) {
+        self.documents.insert(uri, Document { version, text });
+    }
+
+    pub fn close(&mut self, uri: &Url) {
+        self.documents.remove(uri);
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document> {
+        self.documents.get(uri)
+    }
+}
+
+/// A pluggable source of diagnostics over a document's text, so the example
+/// checker below can be swapped out for something that actually understands
+/// the language being edited.
+pub trait DiagnosticSource {
+    fn check(&self, text: &str) -> Vec<Diagnostic>;
+}
+
+/// Flags trailing whitespace on each line. Good enough as the example's
+/// default checker.
+pub struct TrailingWhitespace;
+
+impl DiagnosticSource for TrailingWhitespace {
+    fn check(&self, text: &str) -> Vec<Diagnostic> {
+        text.lines()
+            .enumerate()
+            .filter(|(_, line)| *line != line.trim_end())
+            .map(|(line_no, line)| {
+                let trimmed_len = line.trim_end().len() as u32;
+                let line_len = line.chars().count() as u32;
+                Diagnostic {
+                    range: Range {
+                        start: Position { line: line_no as u32, character: trimmed_len },
+                        end: Position { line: line_no as u32, character: line_len },
+                    },
+                    severity: Some(DiagnosticSeverity::WARNING),
+                    source: Some("rust-lsp-test".to_string()),
+                    message: "trailing whitespace".to_string(),
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Run `source` over the document at `uri` (if it's still open) and publish
+/// the result via `textDocument/publishDiagnostics`, carrying the document's
+/// version along.
+pub fn publish(
+    sender: &Sender<Message>,
+    documents: &DocumentStore,
+    source: &dyn DiagnosticSource,
+    uri: &Url,
+) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let Some(doc) = documents.get(uri) else { return Ok(()) };
+    let params = PublishDiagnosticsParams {
+        uri: uri.clone(),
+        diagnostics: source.check(&doc.text),
+        version: Some(doc.version),
+    };
+    let not = Notification { method: PublishDiagnostics::METHOD.to_string(), params: serde_json::to_value(params)? };
+    sender.send(Message::Notification(not))?;
+    Ok(())
+}
@@ -0,0 +1,218 @@
+//! A small request/notification router, so `main_loop` doesn't have to
+//! hand-roll a `cast::<GotoDefinition>` for every method it wants to
+//! support. Modeled on the dispatcher rust-analyzer builds on top of
+//! `lsp-server`: each `.on::<R>(handler)` call tries `req.extract(R::METHOD)`
+//! and, on a match, runs the handler and sends back a `Response` (populating
+//! `error` if the handler returned one). Whatever isn't claimed by any
+//! handler falls through to a JSON-RPC `MethodNotFound` error instead of
+//! being silently dropped.
+//!
+//! Dispatchers send over a plain `Sender<Message>` rather than a
+//! `Connection`, since they may be running inside a worker thread rather
+//! than the thread that owns the `Connection`'s `receiver`.
+
+use crossbeam_channel::Sender;
+use lsp_server::{
+    ErrorCode,
+    ExtractError,
+    Message,
+    Notification,
+    Request,
+    RequestId,
+    Response,
+    ResponseError,
+};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+pub struct RequestDispatcher<'a> {
+    req: Option<Request>,
+    sender: &'a Sender<Message>,
+    is_active: Box<dyn Fn() -> bool + 'a>,
+}
+
+impl<'a> RequestDispatcher<'a> {
+    pub fn new(req: Request, sender: &'a Sender<Message>) -> Self {
+        RequestDispatcher { req: Some(req), sender, is_active: Box::new(|| true) }
+    }
+
+    /// Suppress the final response if `is_active` says this request is no
+    /// longer live by the time a handler finishes. Without this, a
+    /// `$/cancelRequest` that completes the request (sending
+    /// `RequestCancelled`) while a handler is still running on another
+    /// thread would be followed by a second, stale response once that
+    /// handler finally returns.
+    pub fn require_active(mut self, is_active: impl Fn() -> bool + 'a) -> Self {
+        self.is_active = Box::new(is_active);
+        self
+    }
+
+    /// Registers a handler for `R`. If the request currently being
+    /// dispatched is a `R`, runs `f` and sends the response; otherwise
+    /// leaves the request untouched for the next `.on::<_>()` in the chain.
+    pub fn on<R>(
+        &mut self,
+        f: impl FnOnce(RequestId, R::Params) -> Result<R::Result, ResponseError>,
+    ) -> &mut Self
+    where
+        R: lsp_types::request::Request,
+        R::Params: DeserializeOwned,
+        R::Result: Serialize,
+    {
+        let (id, params) = match self.extract::<R>() {
+            Some(it) => it,
+            None => return self,
+        };
+
+        let response = match f(id.clone(), params) {
+            Ok(result) => Response { id, result: Some(serde_json::to_value(result).unwrap()), error: None },
+            Err(error) => Response { id, result: None, error: Some(error) },
+        };
+        self.send(response);
+        self
+    }
+
+    /// Call once all `.on::<_>()` handlers have been registered. If nothing
+    /// claimed the request, responds with `MethodNotFound` instead of
+    /// dropping it on the floor.
+    pub fn finish(&mut self) {
+        if let Some(req) = self.req.take() {
+            eprintln!("unhandled request: {req:?}");
+            let response = Response {
+                id: req.id,
+                result: None,
+                error: Some(ResponseError {
+                    code: ErrorCode::MethodNotFound as i32,
+                    message: format!("unknown method: {}", req.method),
+                    data: None,
+                }),
+            };
+            self.send(response);
+        }
+    }
+
+    fn extract<R>(&mut self) -> Option<(RequestId, R::Params)>
+    where
+        R: lsp_types::request::Request,
+        R::Params: DeserializeOwned,
+    {
+        let req = self.req.take()?;
+        match req.extract(R::METHOD) {
+            Ok(it) => Some(it),
+            Err(ExtractError::MethodMismatch(req)) => {
+                self.req = Some(req);
+                None
+            }
+            Err(ExtractError::JsonError { method, error }) => {
+                panic!("Invalid request\nMethod: {method}\nError: {error}")
+            }
+        }
+    }
+
+    fn send(&self, response: Response) {
+        if !(self.is_active)() {
+            eprintln!("request #{} was already completed, dropping stale response", response.id);
+            return;
+        }
+        self.sender.send(Message::Response(response)).unwrap();
+    }
+}
+
+pub struct NotificationDispatcher<'a> {
+    not: Option<Notification>,
+    sender: &'a Sender<Message>,
+}
+
+impl<'a> NotificationDispatcher<'a> {
+    pub fn new(not: Notification, sender: &'a Sender<Message>) -> Self {
+        NotificationDispatcher { not: Some(not), sender }
+    }
+
+    pub fn on<N>(&mut self, f: impl FnOnce(&Sender<Message>, N::Params)) -> &mut Self
+    where
+        N: lsp_types::notification::Notification,
+        N::Params: DeserializeOwned,
+    {
+        let not = match self.not.take() {
+            Some(it) => it,
+            None => return self,
+        };
+        match not.extract::<N::Params>(N::METHOD) {
+            Ok(params) => {
+                f(self.sender, params);
+            }
+            Err(ExtractError::MethodMismatch(not)) => {
+                self.not = Some(not);
+            }
+            Err(ExtractError::JsonError { method, error }) => {
+                panic!("Invalid notification\nMethod: {method}\nError: {error}")
+            }
+        }
+        self
+    }
+
+    pub fn finish(&mut self) {
+        if let Some(not) = self.not.take() {
+            eprintln!("unhandled notification: {not:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossbeam_channel::unbounded;
+    use lsp_types::request::{GotoDefinition, HoverRequest, Request as _};
+    use lsp_types::{
+        GotoDefinitionParams,
+        Position,
+        TextDocumentIdentifier,
+        TextDocumentPositionParams,
+    };
+
+    use super::*;
+
+    fn goto_definition_request(id: i32) -> Request {
+        let params = GotoDefinitionParams {
+            text_document_position_params: TextDocumentPositionParams {
+                text_document: TextDocumentIdentifier { uri: "file:///test.rs".parse().unwrap() },
+                position: Position { line: 0, character: 0 },
+            },
+            work_done_progress_params: Default::default(),
+            partial_result_params: Default::default(),
+        };
+        Request::new(RequestId::from(id), GotoDefinition::METHOD.to_string(), params)
+    }
+
+    #[test]
+    fn on_routes_a_matching_request_and_sends_its_response() {
+        let (sender, receiver) = unbounded();
+        RequestDispatcher::new(goto_definition_request(1), &sender)
+            .on::<GotoDefinition>(|_id, _params| Ok(None::<lsp_types::GotoDefinitionResponse>))
+            .on::<HoverRequest>(|_id, _params| panic!("should not reach the hover arm"))
+            .finish();
+
+        let Message::Response(resp) = receiver.try_recv().unwrap() else { panic!("expected a response") };
+        assert_eq!(resp.id, RequestId::from(1));
+        assert!(resp.error.is_none());
+    }
+
+    #[test]
+    fn finish_responds_method_not_found_when_nothing_claims_the_request() {
+        let (sender, receiver) = unbounded();
+        RequestDispatcher::new(goto_definition_request(2), &sender).on::<HoverRequest>(|_id, _params| Ok(None)).finish();
+
+        let Message::Response(resp) = receiver.try_recv().unwrap() else { panic!("expected a response") };
+        assert_eq!(resp.error.unwrap().code, ErrorCode::MethodNotFound as i32);
+    }
+
+    #[test]
+    fn send_drops_the_response_once_require_active_says_no() {
+        let (sender, receiver) = unbounded();
+        RequestDispatcher::new(goto_definition_request(3), &sender)
+            .require_active(|| false)
+            .on::<GotoDefinition>(|_id, _params| Ok(None::<lsp_types::GotoDefinitionResponse>))
+            .finish();
+
+        assert!(receiver.try_recv().is_err(), "response should have been suppressed");
+    }
+}
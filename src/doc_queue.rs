@@ -0,0 +1,36 @@
+//! Per-document ordering on top of the shared [`WorkerPool`]: a `didChange`
+//! and a `codeAction`/`codeAction/resolve` for the same document are
+//! independent jobs, so handing both straight to the pool gives no
+//! guarantee the request's worker won't run (and read `documents`) before
+//! the notification's worker applies the edit. Give each URI its own
+//! single-worker queue instead — work for one document always runs in the
+//! order it arrived, while different documents still run concurrently.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lsp_types::Url;
+
+use crate::worker::WorkerPool;
+
+#[derive(Default)]
+pub struct DocumentQueues {
+    queues: Mutex<HashMap<Url, WorkerPool>>,
+}
+
+impl DocumentQueues {
+    /// Queue `job` behind whatever's already queued for `uri`, creating that
+    /// document's queue on first use.
+    pub fn spawn(&self, uri: &Url, job: impl FnOnce() + Send + 'static) {
+        self.queues.lock().unwrap().entry(uri.clone()).or_insert_with(|| WorkerPool::new(1)).spawn(job);
+    }
+
+    /// Drop `uri`'s queue once its current job (typically the `didClose`
+    /// just spawned) finishes, so a long session doesn't accumulate one
+    /// thread per document ever opened. Already-queued jobs still run:
+    /// dropping the `WorkerPool` only closes its channel, which its worker
+    /// notices after draining what's already in it.
+    pub fn retire(&self, uri: &Url) {
+        self.queues.lock().unwrap().remove(uri);
+    }
+}
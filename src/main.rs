@@ -1,65 +1,92 @@
 #![allow(clippy::print_stderr)]
 
 use std::error::Error;
-use std::fs::File;
-use std::io::{
-    self,
-    BufRead,
-    BufReader,
-    Write
-};
 use std::sync::{
     Arc,
     Mutex
 };
-use std::thread;
-use std::time::Duration;
+
+use crossbeam_channel::RecvTimeoutError;
 use lsp_types::OneOf;
 use lsp_types::{
-    request::GotoDefinition,
+    notification::{
+        Cancel,
+        DidChangeTextDocument,
+        DidCloseTextDocument,
+        DidOpenTextDocument,
+        Exit,
+        Notification as _,
+    },
+    request::{
+        CodeActionRequest,
+        CodeActionResolveRequest,
+        GotoDefinition,
+        HoverRequest,
+        Request as _,
+        Shutdown,
+        WorkDoneProgressCreate,
+    },
+    CodeActionOptions,
     GotoDefinitionResponse,
     InitializeParams,
+    NumberOrString,
     ServerCapabilities,
+    TextDocumentSyncCapability,
+    TextDocumentSyncKind,
+    WorkDoneProgressCreateParams,
     CodeActionProviderCapability,
 };
 use lsp_server::{
-    Connection, ExtractError, Message, ReqQueue, Request, RequestId, Response
+    Connection, Message, Notification, Request, RequestId, Response
 };
 
-use serde::{
-    Deserialize,
-    Serialize
-};
-use serde_json::Value;
-
-#[derive(Serialize, Deserialize, Debug, Clone)]
-struct Command {
-    jsonrpc: String,
-    method: String,
-    id: Option<i32>,
-    params: Value,
-}
+mod socket;
+use socket::Transport;
+
+mod dispatch;
+use dispatch::{NotificationDispatcher, RequestDispatcher};
+
+mod outgoing;
+use outgoing::OutgoingRequests;
+
+mod diagnostics;
+use diagnostics::{DiagnosticSource, DocumentStore, TrailingWhitespace};
+
+mod code_actions;
+
+mod worker;
+use worker::WorkerPool;
+
+mod doc_queue;
+use doc_queue::DocumentQueues;
 
 fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
     eprintln!("Starting LSP Server");
 
-    let (connection, io_threads) = Connection::stdio();
-    let connection = Arc::new(Mutex::new(connection)); // Wrap connection in Arc<Mutex>
-
+    let (connection, io_threads) = match socket::transport_from_args()? {
+        Transport::Stdio => Connection::stdio(),
+        Transport::Socket(addr) => Connection::listen(addr)?,
+        Transport::SocketConnect(addr) => Connection::connect(addr)?,
+    };
     eprintln!("Connection established");
 
     let server_capabilities = serde_json::to_value(&ServerCapabilities {
         definition_provider: Some(OneOf::Left(true)),
-        code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+        // `FULL`, not `INCREMENTAL`: `didChange` below takes the last entry in
+        // `content_changes` as the whole document, which only holds under
+        // `FULL` sync.
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        code_action_provider: Some(CodeActionProviderCapability::Options(CodeActionOptions {
+            code_action_kinds: Some(code_actions::supported_kinds()),
+            resolve_provider: Some(true),
+            work_done_progress_options: Default::default(),
+        })),
         ..Default::default()
     })?;
 
     eprintln!("Initializing LSP Server");
 
-    // Send initial commands
-    // send_initial_commands(&connection)?;
-
-    let initialization_params = match connection.lock().unwrap().initialize(server_capabilities) {
+    let initialization_params = match connection.initialize(server_capabilities) {
         Ok(it) => it,
         Err(e) => {
             if e.channel_is_disconnected() {
@@ -71,130 +98,217 @@ fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
 
     eprintln!("Initialized with params: {:#?}", initialization_params);
 
-    // These txt files contain the exact command that would normally be pasted
-    // into std in. We will read these files and send the commands to the LSP
-    let command_file_paths = vec![
-        "src/json/goto.json",
-        "src/json/shutdown.json",
-        "src/json/exit.json",
-    ];
-
-    // Start the input handler in a separate thread
-    // let connection_clone = Arc::clone(&connection);
-    // // TODO: Read in the input files once every second, and send the commands to
-    // // the server
-    // thread::spawn(move || {
-    //     loop {
-    //         // Wait for a second before checking for commands
-    //         thread::sleep(Duration::from_secs(1));
-    //         for file_path in &command_file_paths {
-    //             if let Err(e) = read_and_send_command(&connection_clone, file_path) {
-    //                 eprintln!("Error reading from {}: {:?}", file_path, e);
-    //             }
-    //         }
-    //     }
-    // });
-
-    main_loop(Arc::clone(&connection), initialization_params)?;
+    main_loop(connection, initialization_params)?;
     io_threads.join()?;
 
     eprintln!("Shutting down LSP Server");
     Ok(())
 }
 
-fn send_initial_commands(connection: &Arc<Mutex<Connection>>) -> Result<(), Box<dyn Error + Sync + Send>> {
-    // Specify the commands you want to send initially
-    let initial_commands = vec![
-        "src/json/initialize.json",
-        "src/json/initialized.json",
-    ];
-
-    for file_path in initial_commands {
-        if let Err(e) = read_and_send_command(connection, file_path) {
-            eprintln!("Error sending initial command from {}: {:?}", file_path, e);
-        }
-    }
-
-    Ok(())
-}
-
-fn read_and_send_command(connection: &Arc<Mutex<Connection>>, file_path: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
-    // Open the command file
-    let file = File::open(file_path)?;
-    let reader: BufReader<File> = BufReader::new(file);
-
-    // Read the json in from the file
-    let file_contents: String = reader
-        .lines()
-        .filter_map(|line| line.ok()) // Filter out errors
-        .collect::<Vec<_>>() // Collect lines into a Vec
-        .join("\n"); // Join with newline to ensure valid JSON if needed
-
-    eprintln!("Reading command from {}: {}", file_path, file_contents);
-
-    // Ensure the JSON is valid
-    let command: Command = serde_json::from_str(&file_contents)?;
-
-    // Send the command to the LSP server
-    let conn = connection.lock().unwrap();
-    conn.sender.send(Message::Request(Request {
-        id: RequestId::from(command.id.unwrap_or(1)), // Handle IDs appropriately
-        method: command.method,
-        params: command.params,
-    }))?;
-
-    Ok(())
-}
-
 fn main_loop(
-    connection: Arc<Mutex<Connection>>,
+    connection: Connection,
     params: serde_json::Value,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
     let _params: InitializeParams = serde_json::from_value(params)?;
     eprintln!("Starting Main Loop");
-    loop {
-        let msg = {
-            let conn = connection.lock().unwrap();
-            match conn.receiver.recv() {
-                Ok(msg) => msg,
-                Err(_) => break, // Exit on error
-            }
-        };
 
+    // No more locking the whole `Connection` on every message: `sender` is a
+    // cloneable channel handle any thread can send on, and `receiver` is
+    // owned by this thread alone. Slow handlers run on `pool` instead of the
+    // reader thread, so they can't block us from getting to the next
+    // message — which matters for `$/cancelRequest` and `shutdown`, both
+    // handled inline below rather than queued behind whatever's running.
+    let Connection { sender, receiver } = connection;
+
+    let pool = WorkerPool::new(4);
+    let document_queues = DocumentQueues::default();
+    let documents = Arc::new(Mutex::new(DocumentStore::default()));
+    let outgoing = Arc::new(Mutex::new(OutgoingRequests::default()));
+    let diagnostic_source: Arc<dyn DiagnosticSource + Send + Sync> = Arc::new(TrailingWhitespace);
+
+    outgoing.lock().unwrap().register_outgoing(
+        &sender,
+        WorkDoneProgressCreate::METHOD,
+        WorkDoneProgressCreateParams { token: NumberOrString::String("rust-lsp-test/startup".to_string()) },
+        Box::new(|resp| eprintln!("workDoneProgress/create acknowledged: {resp:?}")),
+    )?;
+
+    while let Ok(msg) = receiver.recv() {
         match msg {
             Message::Request(req) => {
-                if connection.lock().unwrap().handle_shutdown(&req)? {
-                    return Ok(());
+                // `shutdown` must preempt anything already queued in the pool, so
+                // it's handled inline here rather than going through a worker.
+                // This can't reuse `Connection::handle_shutdown` (which recvs on
+                // a clone of `receiver` while waiting for `exit`): that clone
+                // would race this very loop for the same notification, so
+                // whichever side loses sees `exit` as "unhandled" and the other
+                // times out 30s later. Recv for `exit` on `receiver` itself
+                // instead, since this loop is its only consumer.
+                if req.method == Shutdown::METHOD {
+                    eprintln!("got shutdown request #{}", req.id);
+                    sender.send(Message::Response(Response::new_ok(req.id, ())))?;
+                    return match receiver.recv_timeout(std::time::Duration::from_secs(30)) {
+                        Ok(Message::Notification(n)) if n.method == Exit::METHOD => Ok(()),
+                        Ok(msg) => Err(format!("unexpected message during shutdown: {msg:?}").into()),
+                        Err(RecvTimeoutError::Timeout) => {
+                            Err("timed out waiting for exit notification".into())
+                        }
+                        Err(RecvTimeoutError::Disconnected) => {
+                            Err("channel disconnected waiting for exit notification".into())
+                        }
+                    };
                 }
+
                 eprintln!("got request: {req:?}");
-                match cast::<GotoDefinition>(req) {
-                    Ok((id, params)) => {
-                        eprintln!("got gotoDefinition request #{id}: {params:?}");
-                        let result = Some(GotoDefinitionResponse::Array(Vec::new()));
-                        let result = serde_json::to_value(&result)?;
-                        let resp = Response { id, result: Some(result), error: None };
-                        connection.lock().unwrap().sender.send(Message::Response(resp))?;
-                        continue;
+                let id = req.id.clone();
+                outgoing.lock().unwrap().register_incoming(id.clone());
+                let doc_uri = request_document_uri(&req);
+
+                let sender = sender.clone();
+                let documents = Arc::clone(&documents);
+                let outgoing = Arc::clone(&outgoing);
+                let job: Box<dyn FnOnce() + Send> = Box::new(move || {
+                    if !outgoing.lock().unwrap().is_incoming_active(&id) {
+                        eprintln!("request #{id} was cancelled before it started, skipping");
+                        return;
                     }
-                    Err(err @ ExtractError::JsonError { .. }) => panic!("{err:?}"),
-                    Err(ExtractError::MethodMismatch(req)) => req,
-                };
+                    let guard_outgoing = Arc::clone(&outgoing);
+                    let guard_id = id.clone();
+                    RequestDispatcher::new(req, &sender)
+                        .require_active(move || guard_outgoing.lock().unwrap().is_incoming_active(&guard_id))
+                        .on::<GotoDefinition>(handle_goto_definition)
+                        .on::<HoverRequest>(handle_hover)
+                        .on::<CodeActionRequest>(|id, params| {
+                            eprintln!("got codeAction request #{id}: {params:?}");
+                            let doc = documents.lock().unwrap().get(&params.text_document.uri).cloned();
+                            Ok(Some(code_actions::compute(&params, doc.as_ref())))
+                        })
+                        .on::<CodeActionResolveRequest>(|id, action| {
+                            eprintln!("resolving codeAction #{id}: {}", action.title);
+                            let doc = code_actions::uri_of(&action)
+                                .and_then(|uri| documents.lock().unwrap().get(&uri).cloned());
+                            Ok(code_actions::resolve(action, doc.as_ref()))
+                        })
+                        .finish();
+                    outgoing.lock().unwrap().complete_incoming(&id);
+                });
+                // Requests that read `documents` (codeAction and its resolve)
+                // go through that document's queue instead of the shared
+                // pool, so they can't run ahead of a same-document didChange
+                // still waiting behind them — see `doc_queue`.
+                match doc_uri {
+                    Some(uri) => document_queues.spawn(&uri, job),
+                    None => pool.spawn(job),
+                }
             }
             Message::Response(resp) => {
-                eprintln!("got response: {resp:?}");
+                outgoing.lock().unwrap().complete_outgoing(resp);
             }
             Message::Notification(not) => {
+                // Likewise, `$/cancelRequest` is handled inline rather than
+                // queued, so it can't get stuck behind the very request it's
+                // trying to cancel.
+                if not.method == Cancel::METHOD {
+                    match serde_json::from_value::<lsp_types::CancelParams>(not.params) {
+                        Ok(params) => outgoing.lock().unwrap().handle_cancel(&sender, params)?,
+                        Err(e) => eprintln!("malformed $/cancelRequest, ignoring: {e:?}"),
+                    }
+                    continue;
+                }
+
                 eprintln!("got notification: {not:?}");
+                let doc_uri = notification_document_uri(&not);
+                let is_close = not.method == DidCloseTextDocument::METHOD;
+
+                let sender = sender.clone();
+                let documents = Arc::clone(&documents);
+                let diagnostic_source = Arc::clone(&diagnostic_source);
+                let job: Box<dyn FnOnce() + Send> = Box::new(move || {
+                    NotificationDispatcher::new(not, &sender)
+                        .on::<DidOpenTextDocument>(|sender, params| {
+                            let uri = params.text_document.uri;
+                            eprintln!("didOpen: {uri}");
+                            documents.lock().unwrap().open(uri.clone(), params.text_document.version, params.text_document.text);
+                            let docs = documents.lock().unwrap();
+                            if let Err(e) = diagnostics::publish(sender, &docs, diagnostic_source.as_ref(), &uri) {
+                                eprintln!("failed to publish diagnostics for {uri}: {e:?}");
+                            }
+                        })
+                        .on::<DidChangeTextDocument>(|sender, params| {
+                            let uri = params.text_document.uri;
+                            eprintln!("didChange: {uri}");
+                            if let Some(change) = params.content_changes.into_iter().next_back() {
+                                documents.lock().unwrap().change(uri.clone(), params.text_document.version, change.text);
+                            }
+                            let docs = documents.lock().unwrap();
+                            if let Err(e) = diagnostics::publish(sender, &docs, diagnostic_source.as_ref(), &uri) {
+                                eprintln!("failed to publish diagnostics for {uri}: {e:?}");
+                            }
+                        })
+                        .on::<DidCloseTextDocument>(|_sender, params| {
+                            eprintln!("didClose: {}", params.text_document.uri);
+                            documents.lock().unwrap().close(&params.text_document.uri);
+                        })
+                        .finish();
+                });
+                // Same-document notifications (and the requests routed
+                // alongside them above) must stay in arrival order, so this
+                // goes through `document_queues` rather than the shared pool.
+                match &doc_uri {
+                    Some(uri) => document_queues.spawn(uri, job),
+                    None => pool.spawn(job),
+                }
+                if is_close {
+                    if let Some(uri) = doc_uri {
+                        document_queues.retire(&uri);
+                    }
+                }
             }
         }
     }
     Ok(())
 }
 
-fn cast<R>(req: Request) -> Result<(RequestId, R::Params), ExtractError<Request>>
-where
-    R: lsp_types::request::Request,
-    R::Params: serde::de::DeserializeOwned,
-{
-    req.extract(R::METHOD)
+/// The document a request reads from, if it's one of the kinds that does —
+/// used to route it through that document's queue instead of the shared
+/// pool. `None` means the request doesn't care about `documents` and can
+/// run on whichever worker is free.
+fn request_document_uri(req: &Request) -> Option<lsp_types::Url> {
+    if req.method == CodeActionRequest::METHOD {
+        serde_json::from_value::<lsp_types::CodeActionParams>(req.params.clone()).ok().map(|p| p.text_document.uri)
+    } else if req.method == CodeActionResolveRequest::METHOD {
+        serde_json::from_value::<lsp_types::CodeAction>(req.params.clone()).ok().and_then(|action| code_actions::uri_of(&action))
+    } else {
+        None
+    }
+}
+
+/// The document a notification mutates, if any — same purpose as
+/// `request_document_uri` but for the `Did*TextDocument` notifications.
+fn notification_document_uri(not: &Notification) -> Option<lsp_types::Url> {
+    if not.method == DidOpenTextDocument::METHOD {
+        serde_json::from_value::<lsp_types::DidOpenTextDocumentParams>(not.params.clone()).ok().map(|p| p.text_document.uri)
+    } else if not.method == DidChangeTextDocument::METHOD {
+        serde_json::from_value::<lsp_types::DidChangeTextDocumentParams>(not.params.clone()).ok().map(|p| p.text_document.uri)
+    } else if not.method == DidCloseTextDocument::METHOD {
+        serde_json::from_value::<lsp_types::DidCloseTextDocumentParams>(not.params.clone()).ok().map(|p| p.text_document.uri)
+    } else {
+        None
+    }
+}
+
+fn handle_goto_definition(
+    id: RequestId,
+    params: <GotoDefinition as lsp_types::request::Request>::Params,
+) -> Result<Option<GotoDefinitionResponse>, lsp_server::ResponseError> {
+    eprintln!("got gotoDefinition request #{id}: {params:?}");
+    Ok(Some(GotoDefinitionResponse::Array(Vec::new())))
+}
+
+fn handle_hover(
+    id: RequestId,
+    params: <HoverRequest as lsp_types::request::Request>::Params,
+) -> Result<Option<lsp_types::Hover>, lsp_server::ResponseError> {
+    eprintln!("got hover request #{id}: {params:?}");
+    Ok(None)
 }
@@ -0,0 +1,94 @@
+//! Bookkeeping for requests the *server* sends to the client (e.g.
+//! `window/workDoneProgress/create`, `workspace/configuration`), plus
+//! `$/cancelRequest` handling for requests the client sent *us*. This is
+//! what `lsp_server::ReqQueue` is for, but until now it was imported and
+//! never touched.
+
+use std::error::Error;
+
+use crossbeam_channel::Sender;
+use lsp_server::{
+    Message,
+    ReqQueue,
+    RequestId,
+    Response,
+};
+use lsp_types::{
+    CancelParams,
+    NumberOrString,
+};
+use serde::Serialize;
+
+/// Invoked with the client's reply once a request we sent comes back.
+pub type ResponseHandler = Box<dyn FnOnce(Response) + Send>;
+
+/// Tracks requests we've sent (awaiting a `Response`) and requests the
+/// client has sent us that are still in flight (so `$/cancelRequest` can
+/// find them).
+#[derive(Default)]
+pub struct OutgoingRequests {
+    queue: ReqQueue<(), ResponseHandler>,
+}
+
+impl OutgoingRequests {
+    /// Record that `id` is now being processed, so a later `$/cancelRequest`
+    /// naming it can be honored.
+    pub fn register_incoming(&mut self, id: RequestId) {
+        self.queue.incoming.register(id, ());
+    }
+
+    /// Record that `id`'s response has been sent and it's no longer
+    /// cancellable.
+    pub fn complete_incoming(&mut self, id: &RequestId) {
+        self.queue.incoming.complete(id);
+    }
+
+    /// Whether `id` is still registered as in flight, i.e. hasn't already
+    /// been completed or cancelled. A worker about to dispatch a request
+    /// checks this first so it doesn't send a second response for a request
+    /// `$/cancelRequest` already answered.
+    pub fn is_incoming_active(&self, id: &RequestId) -> bool {
+        !self.queue.incoming.is_completed(id)
+    }
+
+    /// Handle a `$/cancelRequest` notification: if `params.id` names a
+    /// request that's still in flight, send back a JSON-RPC
+    /// `RequestCancelled` (-32800) response for it, as the spec requires.
+    pub fn handle_cancel(
+        &mut self,
+        sender: &Sender<Message>,
+        params: CancelParams,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let id: RequestId = match params.id {
+            NumberOrString::Number(n) => n.into(),
+            NumberOrString::String(s) => s.into(),
+        };
+        if let Some(response) = self.queue.incoming.cancel(id) {
+            sender.send(Message::Response(response))?;
+        }
+        Ok(())
+    }
+
+    /// Send a request of our own, remembering `handler` so the reply can be
+    /// routed to it once it arrives as a `Message::Response`.
+    pub fn register_outgoing<P: Serialize>(
+        &mut self,
+        sender: &Sender<Message>,
+        method: &str,
+        params: P,
+        handler: ResponseHandler,
+    ) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let req = self.queue.outgoing.register(method.to_string(), serde_json::to_value(params)?, handler);
+        sender.send(Message::Request(req))?;
+        Ok(())
+    }
+
+    /// Route a `Message::Response` we received back to the handler stored
+    /// by `register_outgoing`.
+    pub fn complete_outgoing(&mut self, response: Response) {
+        match self.queue.outgoing.complete(response.id.clone()) {
+            Some(handler) => handler(response),
+            None => eprintln!("got response for unknown request: {response:?}"),
+        }
+    }
+}
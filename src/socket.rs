@@ -0,0 +1,35 @@
+//! CLI parsing for which transport to use at startup. The actual TCP
+//! plumbing lives in `lsp_server::Connection::connect`/`::listen` already —
+//! same bounded-channel reader/writer threads, same `Content-Length` framing
+//! as `Connection::stdio` uses, so there's nothing to reimplement here.
+
+use std::error::Error;
+
+/// Transport to use at startup, parsed from CLI args.
+pub enum Transport {
+    Stdio,
+    /// `--socket <addr>`: listen on `addr` and wait for the client to connect.
+    Socket(String),
+    /// `--socket-connect <addr>`: dial `addr`, where the client is already
+    /// listening, instead of waiting for it to connect to us.
+    SocketConnect(String),
+}
+
+/// Parse `--socket <addr>`/`--socket-connect <addr>` out of the process
+/// args, defaulting to stdio.
+pub fn transport_from_args() -> Result<Transport, Box<dyn Error + Sync + Send>> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--socket" {
+            let addr = args.next().ok_or("--socket requires an address, e.g. --socket 127.0.0.1:9257")?;
+            return Ok(Transport::Socket(addr));
+        }
+        if arg == "--socket-connect" {
+            let addr = args
+                .next()
+                .ok_or("--socket-connect requires an address, e.g. --socket-connect 127.0.0.1:9257")?;
+            return Ok(Transport::SocketConnect(addr));
+        }
+    }
+    Ok(Transport::Stdio)
+}
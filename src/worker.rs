@@ -0,0 +1,37 @@
+//! A small worker pool so a slow handler (e.g. a diagnostics pass over a
+//! large document) can't block the reader loop from getting to the next
+//! message — in particular `$/cancelRequest` and `shutdown`, which the
+//! reader loop handles inline rather than queuing here so they always
+//! preempt whatever's already queued.
+
+use std::thread;
+
+use crossbeam_channel::{unbounded, Sender};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct WorkerPool {
+    sender: Sender<Job>,
+}
+
+impl WorkerPool {
+    pub fn new(size: usize) -> Self {
+        let (sender, receiver) = unbounded::<Job>();
+        for _ in 0..size {
+            let receiver = receiver.clone();
+            thread::spawn(move || {
+                for job in receiver {
+                    job();
+                }
+            });
+        }
+        WorkerPool { sender }
+    }
+
+    /// Queue `job` to run on whichever worker picks it up next. Sending only
+    /// fails once every worker has exited (each one panicked, dropping its
+    /// end of the channel), which isn't recoverable anyway.
+    pub fn spawn(&self, job: impl FnOnce() + Send + 'static) {
+        let _ = self.sender.send(Box::new(job));
+    }
+}